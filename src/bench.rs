@@ -0,0 +1,136 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use async_executor::Executor;
+use async_io::{block_on, Async};
+use futures_lite::{future, io, AsyncReadExt, AsyncWriteExt};
+
+use socks5::proxy;
+
+use crate::client::socks5_connect;
+
+macro_rules! help {
+    () => {
+        r#"
+usage: bench [options]
+
+options:
+    -h  show help
+    -c <number> concurrent connections, default 64
+    -n <bytes> payload size of each connection, default 1048576
+"#
+    };
+}
+
+struct Options {
+    connections: usize,
+    size: usize,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, &'static str> {
+    let mut options = Options {
+        connections: 64,
+        size: 1 << 20,
+    };
+    while let Some(opts) = args.next() {
+        match opts.as_str() {
+            "-h" => return Err(concat!(env!("CARGO_PKG_NAME"), " bench\n", help!())),
+            "-c" => options.connections = crate::value(args.next(), "invalid connections.")?,
+            "-n" => options.size = crate::value(args.next(), "invalid payload size.")?,
+            _ => return Err(r#"invalid options, use "bench -h" to show help"#),
+        }
+    }
+    if options.connections == 0 {
+        return Err("connections must be greater than 0.");
+    }
+    Ok(options)
+}
+
+async fn round_trip(server: SocketAddr, backend: SocketAddr, payload: &[u8]) -> Result<Duration> {
+    let start = Instant::now();
+    let mut stream = Async::<TcpStream>::connect(server).await?;
+    socks5_connect(&mut stream, backend.into(), None).await?;
+    let mut echoed = vec![0; payload.len()];
+    let (mut reader, mut writer) = (&stream, &stream);
+    future::try_zip(writer.write_all(payload), reader.read_exact(&mut echoed)).await?;
+    if echoed != payload {
+        return Err(anyhow!("echoed data mismatch"));
+    }
+    Ok(start.elapsed())
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> u128 {
+    sorted[(sorted.len() - 1) * p / 100].as_micros()
+}
+
+fn report(options: &Options, elapsed: Duration, mut latencies: Vec<Duration>) {
+    latencies.sort();
+    // the payload goes to the backend and comes back
+    let bytes = options.connections * options.size * 2;
+    println!("connections={}", options.connections);
+    println!("bytes={}", bytes);
+    println!("elapsed_ms={}", elapsed.as_millis());
+    println!(
+        "throughput_mib_s={:.2}",
+        bytes as f64 / elapsed.as_secs_f64() / (1 << 20) as f64
+    );
+    println!("latency_p50_us={}", percentile(&latencies, 50));
+    println!("latency_p90_us={}", percentile(&latencies, 90));
+    println!("latency_p99_us={}", percentile(&latencies, 99));
+    println!("latency_max_us={}", percentile(&latencies, 100));
+}
+
+/// runs the proxy and an echo backend on loopback, then drives connections
+/// through the proxy to the backend.
+pub fn run(args: impl Iterator<Item = String>) -> Result<()> {
+    let options = parse_args(args).map_err(|e| anyhow!(e))?;
+    let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let server = Async::<TcpListener>::bind(localhost)?;
+    let backend = Async::<TcpListener>::bind(localhost)?;
+    let server_addr = server.get_ref().local_addr()?;
+    let backend_addr = backend.get_ref().local_addr()?;
+    let payload = vec![0x5a; options.size];
+    let executor = Executor::new();
+    block_on(executor.run(async {
+        let proxy_server = async {
+            while let Ok((mut stream, src)) = server.accept().await {
+                executor
+                    .spawn(async move {
+                        if let Err(e) = proxy(&mut stream, src).await {
+                            println!("error: {}", e);
+                        }
+                    })
+                    .detach();
+            }
+        };
+        let echo_server = async {
+            while let Ok((stream, _)) = backend.accept().await {
+                executor
+                    .spawn(async move {
+                        let _ = io::copy(&stream, &mut &stream).await;
+                    })
+                    .detach();
+            }
+        };
+        let bench = async {
+            let start = Instant::now();
+            let tasks: Vec<_> = (0..options.connections)
+                .map(|_| executor.spawn(round_trip(server_addr, backend_addr, &payload)))
+                .collect();
+            let mut latencies = Vec::with_capacity(tasks.len());
+            for task in tasks {
+                latencies.push(task.await?);
+            }
+            report(&options, start.elapsed(), latencies);
+            Ok::<_, anyhow::Error>(())
+        };
+        future::or(bench, async {
+            future::zip(proxy_server, echo_server).await;
+            Err(anyhow!("benchmark listener closed"))
+        })
+        .await
+    }))
+}
@@ -0,0 +1,470 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+};
+
+use futures_lite::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// the destination of a CONNECT request.
+pub enum Address<'a> {
+    Socket(SocketAddr),
+    /// a domain name, resolved by the server, and a port.
+    // bench only connects to addresses
+    #[cfg_attr(not(test), allow(dead_code))]
+    Domain(&'a str, u16),
+}
+
+impl From<SocketAddr> for Address<'_> {
+    fn from(addr: SocketAddr) -> Self {
+        Address::Socket(addr)
+    }
+}
+
+fn invalid_data(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn check_version(version: u8, expected: u8, what: &str) -> Result<()> {
+    if version != expected {
+        return Err(invalid_data(format!(
+            "unexpected version of {}: {}",
+            what, version
+        )));
+    }
+    Ok(())
+}
+
+// username/password authentication, RFC 1929
+async fn authenticate<S>(stream: &mut S, username: &str, password: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (username, password) = (username.as_bytes(), password.as_bytes());
+    if username.len() > u8::MAX as usize || password.len() > u8::MAX as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "username or password is too long",
+        ));
+    }
+    let mut request = vec![1, username.len() as u8];
+    request.extend_from_slice(username);
+    request.push(password.len() as u8);
+    request.extend_from_slice(password);
+    stream.write_all(&request).await?;
+
+    let mut status = [0; 2];
+    stream.read_exact(&mut status).await?;
+    check_version(status[0], 1, "authentication status")?;
+    if status[1] != 0 {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "authentication failed",
+        ));
+    }
+    Ok(())
+}
+
+/// performs a client handshake on `stream`, then asks the server to connect
+/// to `target`, returns once the tunnel is ready for relay.
+///
+/// `auth` is a pair of username and password, offered besides no
+/// authentication if present.
+pub async fn socks5_connect<S>(
+    stream: &mut S,
+    target: Address<'_>,
+    auth: Option<(&str, &str)>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = vec![5, 1, 0];
+    match target {
+        Address::Socket(SocketAddr::V4(addr)) => {
+            request.push(1);
+            request.extend_from_slice(&addr.ip().octets());
+            request.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        Address::Socket(SocketAddr::V6(addr)) => {
+            request.push(4);
+            request.extend_from_slice(&addr.ip().octets());
+            request.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        Address::Domain(domain, port) => {
+            if domain.is_empty() || domain.len() > u8::MAX as usize {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid domain length: {}", domain.len()),
+                ));
+            }
+            request.push(3);
+            request.push(domain.len() as u8);
+            request.extend_from_slice(domain.as_bytes());
+            request.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+
+    let methods: &[u8] = match auth {
+        Some(_) => &[5, 2, 0, 2],
+        None => &[5, 1, 0],
+    };
+    stream.write_all(methods).await?;
+    let mut method = [0; 2];
+    stream.read_exact(&mut method).await?;
+    check_version(method[0], 5, "method selection")?;
+    match (method[1], auth) {
+        (0, _) => (),
+        (2, Some((username, password))) => authenticate(stream, username, password).await?,
+        _ => {
+            return Err(invalid_data(format!(
+                "unexpected method selection: {}",
+                method[1]
+            )))
+        }
+    }
+
+    stream.write_all(&request).await?;
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    check_version(reply[0], 5, "reply")?;
+    if reply[1] != 0 {
+        return Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("connect failed, reply: {}", reply[1]),
+        ));
+    }
+    let len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        atyp => return Err(invalid_data(format!("unsupported address type: {}", atyp))),
+    };
+    // bound address and port
+    let mut bound = vec![0; len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        io::{Error, ErrorKind, Result},
+        net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    };
+
+    use async_io::{block_on, Async};
+    use futures_lite::{future, io, AsyncReadExt, AsyncWriteExt};
+    use socks5::proxy;
+
+    use super::{socks5_connect, Address};
+
+    // a successful CONNECT reply, bound to 0.0.0.0:0
+    const SUCCEEDED: &[u8] = &[5, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+
+    fn localhost() -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, 0))
+    }
+
+    /// runs `client` with the address of a proxy on loopback, which serves a
+    /// single connection and closes it once `proxy` returns.
+    fn through_proxy<C, F>(client: C) -> F::Output
+    where
+        C: FnOnce(SocketAddr) -> F,
+        F: Future,
+    {
+        let server = Async::<TcpListener>::bind(localhost()).unwrap();
+        let server_addr = server.get_ref().local_addr().unwrap();
+        let proxy_server = async {
+            let (mut stream, src) = server.accept().await.unwrap();
+            let _ = proxy(&mut stream, src).await;
+            drop(stream);
+            future::pending().await
+        };
+        block_on(future::or(client(server_addr), proxy_server))
+    }
+
+    /// echoes a single connection on `listener` until `len` bytes are
+    /// received, returns them.
+    async fn echo(listener: &Async<TcpListener>, len: usize) -> Result<Vec<u8>> {
+        let (mut stream, _) = listener.accept().await?;
+        let mut received = Vec::with_capacity(len);
+        let mut buf = [0; 4096];
+        while received.len() < len {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            received.extend_from_slice(&buf[..n]);
+            stream.write_all(&buf[..n]).await?;
+        }
+        Ok(received)
+    }
+
+    /// a CONNECT request to 127.0.0.1:`port`.
+    fn connect_request(port: u16) -> Vec<u8> {
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&port.to_be_bytes());
+        request
+    }
+
+    #[test]
+    fn connects_through_the_server() {
+        let server = Async::<TcpListener>::bind(localhost()).unwrap();
+        let backend = Async::<TcpListener>::bind(localhost()).unwrap();
+        let server_addr = server.get_ref().local_addr().unwrap();
+        let backend_addr = backend.get_ref().local_addr().unwrap();
+        let proxy_server = async {
+            let (mut stream, src) = server.accept().await.unwrap();
+            let _ = proxy(&mut stream, src).await;
+        };
+        let echo_server = async {
+            let (stream, _) = backend.accept().await.unwrap();
+            let _ = io::copy(&stream, &mut &stream).await;
+        };
+        let client = async {
+            let mut stream = Async::<TcpStream>::connect(server_addr).await?;
+            socks5_connect(&mut stream, backend_addr.into(), None).await?;
+            stream.write_all(b"ping").await?;
+            let mut echoed = [0; 4];
+            stream.read_exact(&mut echoed).await?;
+            Ok(echoed)
+        };
+        let echoed = block_on(future::or(client, async {
+            future::zip(proxy_server, echo_server).await;
+            Err(Error::new(ErrorKind::Other, "server closed"))
+        }))
+        .unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+
+    /// runs the handshake against a server which expects each request of
+    /// `script` and answers it with the reply next to it.
+    fn scripted(
+        script: &[(&[u8], &[u8])],
+        target: Address<'_>,
+        auth: Option<(&str, &str)>,
+    ) -> Result<()> {
+        let listener = Async::<TcpListener>::bind(localhost()).unwrap();
+        let server_addr = listener.get_ref().local_addr().unwrap();
+        let server = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            for (expected, reply) in script {
+                let mut request = vec![0; expected.len()];
+                stream.read_exact(&mut request).await.unwrap();
+                assert_eq!(&request, expected);
+                stream.write_all(reply).await.unwrap();
+            }
+        };
+        let client = async {
+            let mut stream = Async::<TcpStream>::connect(server_addr).await?;
+            socks5_connect(&mut stream, target, auth).await
+        };
+        block_on(future::zip(server, client)).1
+    }
+
+    #[test]
+    fn connects_to_a_domain() {
+        let request = [&[5, 1, 0, 3, 11][..], b"example.com", &[1, 187]].concat();
+        let script: &[(&[u8], &[u8])] = &[(&[5, 1, 0], &[5, 0]), (&request, SUCCEEDED)];
+        scripted(script, Address::Domain("example.com", 443), None).unwrap();
+    }
+
+    #[test]
+    fn rejects_an_invalid_domain() {
+        let domain = "a".repeat(256);
+        let err = scripted(&[], Address::Domain(&domain, 443), None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn authenticates_with_password() {
+        let target = SocketAddr::from((Ipv4Addr::LOCALHOST, 80));
+        let script: &[(&[u8], &[u8])] = &[
+            (&[5, 2, 0, 2], &[5, 2]),
+            (b"\x01\x04user\x06secret", &[1, 0]),
+            (&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80], SUCCEEDED),
+        ];
+        scripted(script, target.into(), Some(("user", "secret"))).unwrap();
+    }
+
+    #[test]
+    fn authentication_failure() {
+        let target = SocketAddr::from((Ipv4Addr::LOCALHOST, 80));
+        let script: &[(&[u8], &[u8])] = &[
+            (&[5, 2, 0, 2], &[5, 2]),
+            (b"\x01\x04user\x05wrong", &[1, 1]),
+        ];
+        let err = scripted(script, target.into(), Some(("user", "wrong"))).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn checks_versions() {
+        let target = SocketAddr::from((Ipv4Addr::LOCALHOST, 80));
+        let invalid = |script: &[(&[u8], &[u8])], auth| {
+            let err = scripted(script, target.into(), auth).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        };
+        // method selection
+        invalid(&[(&[5, 1, 0], &[4, 0])], None);
+        // authentication status
+        invalid(
+            &[(&[5, 2, 0, 2], &[5, 2]), (b"\x01\x01u\x01p", &[5, 0])],
+            Some(("u", "p")),
+        );
+        // reply
+        let request: &[u8] = &[5, 1, 0, 1, 127, 0, 0, 1, 0, 80];
+        invalid(
+            &[
+                (&[5, 1, 0], &[5, 0]),
+                (request, &[4, 0, 0, 1, 0, 0, 0, 0, 0, 0]),
+            ],
+            None,
+        );
+    }
+
+    #[test]
+    fn connect_failure() {
+        let target = SocketAddr::from((Ipv4Addr::LOCALHOST, 80));
+        let request: &[u8] = &[5, 1, 0, 1, 127, 0, 0, 1, 0, 80];
+        // connection refused
+        let script: &[(&[u8], &[u8])] = &[
+            (&[5, 1, 0], &[5, 0]),
+            (request, &[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]),
+        ];
+        let err = scripted(script, target.into(), None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn password_only_is_not_acceptable() {
+        let reply = through_proxy(|addr| async move {
+            let mut stream = Async::<TcpStream>::connect(addr).await?;
+            stream.write_all(&[5, 1, 2]).await?;
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).await?;
+            Ok::<_, Error>(reply)
+        })
+        .unwrap();
+        // and closed, or it would not have returned
+        assert_eq!(reply, [5, 0xff]);
+    }
+
+    #[test]
+    fn no_authentication_is_selected() {
+        let reply = through_proxy(|addr| async move {
+            let mut stream = Async::<TcpStream>::connect(addr).await?;
+            stream.write_all(&[5, 1, 0]).await?;
+            let mut reply = [0; 2];
+            stream.read_exact(&mut reply).await?;
+            Ok::<_, Error>(reply)
+        })
+        .unwrap();
+        assert_eq!(reply, [5, 0]);
+    }
+
+    #[test]
+    fn unsupported_address_type_is_replied() {
+        let reply = through_proxy(|addr| async move {
+            let mut stream = Async::<TcpStream>::connect(addr).await?;
+            stream.write_all(&[5, 1, 0]).await?;
+            let mut method = [0; 2];
+            stream.read_exact(&mut method).await?;
+            // there is no address type 5
+            stream.write_all(&[5, 1, 0, 5]).await?;
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).await?;
+            Ok::<_, Error>(reply)
+        })
+        .unwrap();
+        // address type not supported, received before the connection ends
+        assert!(reply.starts_with(&[5, 8]), "reply: {:?}", reply);
+    }
+
+    #[test]
+    fn pipelined_handshake() {
+        let backend = Async::<TcpListener>::bind(localhost()).unwrap();
+        let backend_port = backend.get_ref().local_addr().unwrap().port();
+        let backend = &backend;
+        let (echoed, received) = through_proxy(|addr| async move {
+            let client = async {
+                let mut stream = Async::<TcpStream>::connect(addr).await?;
+                // method selection, request and payload, without waiting for replies
+                let mut pipelined = vec![5, 1, 0];
+                pipelined.extend_from_slice(&connect_request(backend_port));
+                pipelined.extend_from_slice(b"ping");
+                stream.write_all(&pipelined).await?;
+
+                let mut reply = [0; 6];
+                stream.read_exact(&mut reply).await?;
+                assert_eq!(reply[..5], [5, 0, 5, 0, 0]);
+                let bound = match reply[5] {
+                    1 => 4,
+                    4 => 16,
+                    atyp => panic!("unexpected bound address type: {}", atyp),
+                };
+                stream.read_exact(&mut vec![0; bound + 2]).await?;
+                let mut echoed = [0; 4];
+                stream.read_exact(&mut echoed).await?;
+                Ok(echoed)
+            };
+            future::try_zip(client, echo(backend, 4)).await
+        })
+        .unwrap();
+        assert_eq!(&received, b"ping");
+        assert_eq!(&echoed, b"ping");
+    }
+
+    #[test]
+    fn relays_both_directions_at_once() {
+        let backend = Async::<TcpListener>::bind(localhost()).unwrap();
+        let backend_addr = backend.get_ref().local_addr().unwrap();
+        let backend = &backend;
+        // many times any copy buffer
+        let payload: Vec<u8> = (0..4 << 20).map(|i| (i % 251) as u8).collect();
+        let payload = &payload;
+        let (echoed, received) = through_proxy(|addr| async move {
+            let client = async {
+                let mut stream = Async::<TcpStream>::connect(addr).await?;
+                socks5_connect(&mut stream, backend_addr.into(), None).await?;
+                // sent while the echo comes back
+                let mut echoed = vec![0; payload.len()];
+                let (mut reader, mut writer) = (&stream, &stream);
+                future::try_zip(writer.write_all(payload), reader.read_exact(&mut echoed)).await?;
+                Ok(echoed)
+            };
+            future::try_zip(client, echo(backend, payload.len())).await
+        })
+        .unwrap();
+        assert!(&received == payload, "received by the backend differs");
+        assert!(&echoed == payload, "echoed to the client differs");
+    }
+
+    #[test]
+    fn connect_failure_is_replied() {
+        // a port nothing listens on, once the listener is dropped
+        let closed_port = Async::<TcpListener>::bind(localhost())
+            .unwrap()
+            .get_ref()
+            .local_addr()
+            .unwrap()
+            .port();
+        let reply = through_proxy(|addr| async move {
+            let mut stream = Async::<TcpStream>::connect(addr).await?;
+            stream.write_all(&[5, 1, 0]).await?;
+            let mut method = [0; 2];
+            stream.read_exact(&mut method).await?;
+            stream.write_all(&connect_request(closed_port)).await?;
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).await?;
+            Ok::<_, Error>(reply)
+        })
+        .unwrap();
+        // connection refused, received before the connection ends
+        assert!(reply.starts_with(&[5, 5]), "reply: {:?}", reply);
+    }
+}
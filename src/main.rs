@@ -1,63 +1,546 @@
-use std::net::{TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::{
+    any::Any,
+    fmt::Display,
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    panic::AssertUnwindSafe,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use anyhow::{anyhow, Result};
 use async_executor::Executor;
-use async_io::{block_on, Async};
+use async_io::{block_on, Async, Timer};
+use futures_lite::{future, FutureExt};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
 
 use socks5::proxy;
 
+use crate::{rate_limit::RateLimiter, registry::Registry};
+
+#[cfg(feature = "bench")]
+mod bench;
+#[cfg(any(test, feature = "bench"))]
+mod client;
+mod proxy_protocol;
+mod rate_limit;
+mod registry;
+#[cfg(all(feature = "transparent", target_os = "linux"))]
+mod transparent;
+
 macro_rules! help {
     () => {
         r#"
 options:
     -h  show help
     -l <address> assgin a listen address
+    -f <fd> serve on an inherited listening socket, e.g. from systemd socket activation (unix only)
+    -r <bytes> set the receive buffer size (SO_RCVBUF) of client sockets
+    -s <bytes> set the send buffer size (SO_SNDBUF) of client sockets
+    -c <rate> limit new connections per second from a client address
+    -b <number> burst of new connections allowed above the rate, default to the rate
+    -m <length> prefix length IPv6 clients are aggregated by when rate limiting, default 64
+    -d <millis> delay before closing connections over the rate
+    -t <seconds> close connections lasting longer, from handshake to the end of relay
+    -P  require a PROXY protocol (v1 or v2) header on connections and take the client address from it
+    -x  close rejected connections with a TCP RST instead of a graceful FIN
+    -q  do not log rejected connections
+    -T  relay intercepted connections to their original destination, without socks5 handshake (Linux, `transparent` feature)
+    -i <seconds> close transparent relays with no data in either direction for longer (Linux, `transparent` feature)
+    -S  refuse to start, rather than warn, when listening on a non-loopback address
     -V  show version
 "#
     };
 }
 
-fn run(addr: &str) -> Result<()> {
-    let addr = addr
+struct Options {
+    listen: String,
+    #[cfg(unix)]
+    fd: Option<RawFd>,
+    // socket buffer sizes, the OS may clamp (or, on Linux, double) them.
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    rate_limit: Option<f64>,
+    burst: Option<f64>,
+    ipv6_prefix: u8,
+    reject_delay: Duration,
+    // the whole lifetime of a connection, unlimited if `None`
+    deadline: Option<Duration>,
+    proxy_protocol: bool,
+    reset_rejected: bool,
+    log_rejected: bool,
+    // refuse to start as an open proxy instead of warning
+    strict: bool,
+    #[cfg(all(feature = "transparent", target_os = "linux"))]
+    transparent: bool,
+    // of transparent relays, unlimited if `None`
+    #[cfg(all(feature = "transparent", target_os = "linux"))]
+    idle_timeout: Option<Duration>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            listen: "127.0.0.1:1080".to_owned(),
+            #[cfg(unix)]
+            fd: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            rate_limit: None,
+            burst: None,
+            ipv6_prefix: 64,
+            reject_delay: Duration::ZERO,
+            deadline: None,
+            proxy_protocol: false,
+            reset_rejected: false,
+            log_rejected: true,
+            strict: false,
+            #[cfg(all(feature = "transparent", target_os = "linux"))]
+            transparent: false,
+            #[cfg(all(feature = "transparent", target_os = "linux"))]
+            idle_timeout: None,
+        }
+    }
+}
+
+impl Options {
+    fn burst(&self, rate: f64) -> f64 {
+        self.burst.unwrap_or_else(|| rate.max(1.0))
+    }
+
+    fn mode(&self) -> String {
+        #[cfg(all(feature = "transparent", target_os = "linux"))]
+        if self.transparent {
+            // relayed without handshake, so no methods
+            let idle_timeout = self.idle_timeout.map_or_else(
+                || "unlimited".to_owned(),
+                |timeout| format!("{}s", timeout.as_secs()),
+            );
+            return format!("transparent, idle timeout: {}", idle_timeout);
+        }
+        "socks5, methods: no authentication".to_owned()
+    }
+
+    /// the effective configuration, logged at startup.
+    fn summary(&self, addr: SocketAddr) -> String {
+        #[cfg(unix)]
+        let listening = match self.fd {
+            Some(fd) => format!("{} (inherited fd {})", addr, fd),
+            None => addr.to_string(),
+        };
+        #[cfg(not(unix))]
+        let listening = addr;
+        let buffer_size = |size: Option<usize>| {
+            size.map_or_else(|| "default".to_owned(), |size| size.to_string())
+        };
+        let rate_limit = match self.rate_limit {
+            Some(rate) => format!(
+                "{}/s, burst {}, IPv6 per /{}, reject delay {}ms",
+                rate,
+                self.burst(rate),
+                self.ipv6_prefix,
+                self.reject_delay.as_millis()
+            ),
+            None => "none".to_owned(),
+        };
+        let deadline = self.deadline.map_or_else(
+            || "unlimited".to_owned(),
+            |deadline| format!("{}s", deadline.as_secs()),
+        );
+        format!(
+            "{} {} listening on {}, mode: {}, receive buffer: {}, send buffer: {}, rate limit: {}, deadline: {}, PROXY protocol: {}, rejected connections: {}, reject log: {}, non-loopback listener: {}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            listening,
+            self.mode(),
+            buffer_size(self.recv_buffer_size),
+            buffer_size(self.send_buffer_size),
+            rate_limit,
+            deadline,
+            if self.proxy_protocol { "required" } else { "off" },
+            if self.reset_rejected { "reset" } else { "closed" },
+            if self.log_rejected { "on" } else { "off" },
+            if self.strict { "refused" } else { "warned" },
+        )
+    }
+}
+
+fn value<T: FromStr>(arg: Option<String>, err: &'static str) -> Result<T, &'static str> {
+    arg.and_then(|v| v.parse().ok()).ok_or(err)
+}
+
+fn parse_args() -> Result<Options, &'static str> {
+    let mut options = Options::default();
+    let mut args = std::env::args();
+    args.next(); // skip app's name
+    while let Some(opts) = args.next() {
+        match opts.as_str() {
+            "-h" => return Err(concat!(env!("CARGO_PKG_NAME"), "\n", help!())),
+            "-l" => match args.next() {
+                Some(listen) => options.listen = listen,
+                None => return Err("invalid listen argument, required a value."),
+            },
+            #[cfg(unix)]
+            "-f" => options.fd = Some(value(args.next(), "invalid file descriptor.")?),
+            "-r" => {
+                options.recv_buffer_size = Some(value(args.next(), "invalid receive buffer size.")?)
+            }
+            "-s" => {
+                options.send_buffer_size = Some(value(args.next(), "invalid send buffer size.")?)
+            }
+            "-c" => match value::<f64>(args.next(), "invalid rate.")? {
+                rate if rate > 0.0 => options.rate_limit = Some(rate),
+                _ => return Err("rate must be greater than 0."),
+            },
+            "-b" => match value::<f64>(args.next(), "invalid burst.")? {
+                burst if burst >= 1.0 => options.burst = Some(burst),
+                _ => return Err("burst must be at least 1."),
+            },
+            "-m" => match value::<u8>(args.next(), "invalid prefix length.")? {
+                prefix if prefix <= 128 => options.ipv6_prefix = prefix,
+                _ => return Err("prefix length must be at most 128."),
+            },
+            "-d" => {
+                options.reject_delay = Duration::from_millis(value(args.next(), "invalid delay.")?)
+            }
+            "-t" => match value::<u64>(args.next(), "invalid deadline.")? {
+                deadline if deadline > 0 => options.deadline = Some(Duration::from_secs(deadline)),
+                _ => return Err("deadline must be greater than 0."),
+            },
+            "-P" => options.proxy_protocol = true,
+            "-x" => options.reset_rejected = true,
+            "-q" => options.log_rejected = false,
+            #[cfg(all(feature = "transparent", target_os = "linux"))]
+            "-T" => options.transparent = true,
+            #[cfg(all(feature = "transparent", target_os = "linux"))]
+            "-i" => match value::<u64>(args.next(), "invalid idle timeout.")? {
+                timeout if timeout > 0 => options.idle_timeout = Some(Duration::from_secs(timeout)),
+                _ => return Err("idle timeout must be greater than 0."),
+            },
+            "-S" => options.strict = true,
+            "-V" => return Err(env!("CARGO_PKG_VERSION")),
+            _ => return Err(r#"invalid options, use "-h" to show help"#),
+        }
+    }
+    Ok(options)
+}
+
+// accepted sockets inherit them from the listener
+fn set_buffer_size(socket: &Socket, options: &Options) -> std::io::Result<()> {
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn socket_option(fd: RawFd, name: libc::c_int) -> std::io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+#[cfg(unix)]
+fn socket_family(fd: RawFd) -> std::io::Result<libc::c_int> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(addr.ss_family as libc::c_int)
+}
+
+#[cfg(unix)]
+fn inherited_listener(fd: RawFd) -> Result<TcpListener> {
+    // check the descriptor before taking its ownership, so a wrong one is not closed.
+    let invalid = |e| anyhow!("invalid file descriptor {}: {}", fd, e);
+    if socket_option(fd, libc::SO_ACCEPTCONN).map_err(invalid)? == 0 {
+        return Err(anyhow!("file descriptor {} is not a listening socket", fd));
+    }
+    let family = socket_family(fd).map_err(invalid)?;
+    if family != libc::AF_INET && family != libc::AF_INET6 {
+        return Err(anyhow!("file descriptor {} is not an IPv4/IPv6 socket", fd));
+    }
+    if socket_option(fd, libc::SO_TYPE).map_err(invalid)? != libc::SOCK_STREAM {
+        return Err(anyhow!("file descriptor {} is not a TCP socket", fd));
+    }
+    Ok(unsafe { TcpListener::from_raw_fd(fd) })
+}
+
+fn listen(options: &Options) -> Result<Async<TcpListener>> {
+    #[cfg(unix)]
+    if let Some(fd) = options.fd {
+        let listener = inherited_listener(fd)?;
+        // already listening, so only connections arriving from now on get them
+        set_buffer_size(&SockRef::from(&listener), options)?;
+        return Ok(Async::new(listener)?);
+    }
+    let addr = options
+        .listen
         .to_socket_addrs()?
         .next()
         .ok_or_else(|| anyhow!("invalid listen address"))?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // as `TcpListener::bind` does
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    // set before `listen`, the window scale is negotiated in the handshake
+    set_buffer_size(&socket, options)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(Async::new(TcpListener::from(socket))?)
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(msg) => msg,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("unknown", String::as_str),
+    }
+}
+
+/// logs a rejected connection and prepares it to be closed, by dropping it
+/// afterwards.
+fn reject(
+    stream: &Async<TcpStream>,
+    id: u64,
+    src: SocketAddr,
+    stage: &str,
+    reason: &dyn Display,
+    options: &Options,
+) {
+    if options.log_rejected {
+        println!(
+            "reject: id={} client={} stage={} reason={}",
+            id, src, stage, reason
+        );
+    }
+    if options.reset_rejected {
+        // SO_LINGER with zero timeout closes with RST instead of FIN
+        if let Err(e) = SockRef::from(stream.get_ref()).set_linger(Some(Duration::ZERO)) {
+            println!("error: id={} {}", id, e);
+        }
+    }
+}
+
+/// logs how a connection ended, `destination` is only known in transparent
+/// mode.
+fn log_close(id: u64, client: SocketAddr, destination: Option<SocketAddr>, reason: &dyn Display) {
+    let destination = destination.map_or_else(|| "-".to_owned(), |dst| dst.to_string());
+    println!(
+        "close: id={} client={} destination={} reason={}",
+        id, client, destination, reason
+    );
+}
+
+/// state shared by the connections of a listener.
+struct Server<'a> {
+    options: &'a Options,
+    #[cfg(all(feature = "transparent", target_os = "linux"))]
+    local_addr: SocketAddr,
+    limiter: Option<Mutex<RateLimiter>>,
+    registry: Arc<Registry>,
+}
+
+#[cfg(all(feature = "transparent", target_os = "linux"))]
+async fn serve_transparent(
+    stream: Async<TcpStream>,
+    id: u64,
+    src: SocketAddr,
+    server: &Server<'_>,
+) {
+    let options = server.options;
+    let dst = match transparent::original_destination(stream.get_ref()) {
+        Ok(dst) => dst,
+        Err(e) => return reject(&stream, id, src, "transparent", &e, options),
+    };
+    // made to the listener itself rather than intercepted, relaying it would loop
+    let local = server.local_addr;
+    if dst.port() == local.port() && (local.ip().is_unspecified() || dst.ip() == local.ip()) {
+        let reason = &"not an intercepted connection";
+        return reject(&stream, id, src, "transparent", reason, options);
+    }
+    server.registry.set_destination(id, dst);
+    match transparent::relay(stream, dst, options.idle_timeout).await {
+        Ok(reason) => log_close(id, src, Some(dst), &reason),
+        Err(e) => {
+            log_close(id, src, Some(dst), &"error");
+            println!("error: id={} {}: {}", id, dst, e);
+        }
+    }
+}
+
+async fn serve(mut stream: Async<TcpStream>, id: u64, peer: SocketAddr, server: &Server<'_>) {
+    let options = server.options;
+    let src = if options.proxy_protocol {
+        match proxy_protocol::read_header(&mut stream).await {
+            Ok(src) => src.unwrap_or(peer),
+            Err(e) => return reject(&stream, id, peer, "proxy-protocol", &e, options),
+        }
+    } else {
+        peer
+    };
+    server.registry.set_client(id, src);
+    if let Some(limiter) = &server.limiter {
+        let allowed = limiter.lock().unwrap().check(src.ip());
+        if !allowed {
+            // holding the connection a while slows scanners down
+            if !options.reject_delay.is_zero() {
+                Timer::after(options.reject_delay).await;
+            }
+            return reject(&stream, id, src, "rate-limit", &"rate exceeded", options);
+        }
+    }
+    #[cfg(all(feature = "transparent", target_os = "linux"))]
+    if options.transparent {
+        return serve_transparent(stream, id, src, server).await;
+    }
+    if let Err(e) = proxy(&mut stream, src).await {
+        println!("error: id={} {}", id, e);
+    }
+}
+
+async fn handle(stream: Async<TcpStream>, id: u64, peer: SocketAddr, server: &Server<'_>) {
+    // before the PROXY protocol header, so connections stalling in it are listed
+    let _registration = server.registry.register(id, peer);
+    // the client is taken from the PROXY protocol header once it is read
+    let addresses = || server.registry.addresses(id).unwrap_or((peer, None));
+    let connection = async {
+        let connection = serve(stream, id, peer, server);
+        match server.options.deadline {
+            Some(deadline) => {
+                let expire = async {
+                    Timer::after(deadline).await;
+                    let (client, destination) = addresses();
+                    log_close(id, client, destination, &"deadline");
+                };
+                future::or(connection, expire).await
+            }
+            None => connection.await,
+        }
+    };
+    // a panic would otherwise unwind through the executor and stop the server,
+    // catching it needs `panic = "unwind"`, as in the release profile.
+    if let Err(e) = AssertUnwindSafe(connection).catch_unwind().await {
+        println!(
+            "error: id={} connection from {} panicked: {}",
+            id,
+            addresses().0,
+            panic_message(&*e)
+        );
+    }
+}
+
+fn is_loopback(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback(),
+        IpAddr::V6(ip) => ip
+            .to_ipv4_mapped()
+            .map_or(ip.is_loopback(), |ip| ip.is_loopback()),
+    }
+}
+
+/// there is no authentication, so listening beyond loopback makes an open
+/// proxy, which should never happen by accident.
+fn check_open_proxy(local_addr: SocketAddr, options: &Options) -> Result<()> {
+    #[cfg(all(feature = "transparent", target_os = "linux"))]
+    if options.transparent {
+        // connections to the listener itself are rejected
+        return Ok(());
+    }
+    if is_loopback(local_addr.ip()) {
+        return Ok(());
+    }
+    if options.strict {
+        return Err(anyhow!(
+            "refusing to run an open proxy on {} without authentication",
+            local_addr
+        ));
+    }
+    println!(
+        "warning: listening on {} without authentication, anyone reaching it can use this proxy",
+        local_addr
+    );
+    Ok(())
+}
+
+fn run(options: &Options) -> Result<()> {
+    let listener = listen(options)?;
+    let local_addr = listener.get_ref().local_addr()?;
+    println!("{}", options.summary(local_addr));
+    check_open_proxy(local_addr, options)?;
+    #[cfg(all(feature = "transparent", target_os = "linux"))]
+    if options.transparent {
+        // only TPROXY needs it, REDIRECT works without
+        if let Err(e) = transparent::set_transparent(listener.get_ref()) {
+            println!("warning: can not accept TPROXY connections: {}", e);
+        }
+    }
+    let server = Server {
+        options,
+        #[cfg(all(feature = "transparent", target_os = "linux"))]
+        local_addr,
+        limiter: options.rate_limit.map(|rate| {
+            Mutex::new(RateLimiter::new(
+                rate,
+                options.burst(rate),
+                options.ipv6_prefix,
+            ))
+        }),
+        registry: Arc::default(),
+    };
+    #[cfg(unix)]
+    registry::dump_on_signal(server.registry.clone())?;
     let executor = Executor::new();
     block_on(executor.run(async {
-        let listener = Async::<TcpListener>::bind(addr)?;
+        // correlates the log lines of a connection
+        let mut id: u64 = 0;
         loop {
-            let (mut stream, src) = listener.accept().await?;
-            executor
-                .spawn(async move {
-                    if let Err(e) = proxy(&mut stream, src).await {
-                        println!("error: {}", e);
-                    }
-                })
-                .detach();
+            let (stream, peer) = listener.accept().await?;
+            id += 1;
+            executor.spawn(handle(stream, id, peer, &server)).detach();
         }
     }))
 }
 
 fn main() {
-    let mut args = std::env::args();
-    args.next(); // skip app's name
-    let listen = match args.next() {
-        Some(opts) => match opts.as_str() {
-            "-h" => Err(concat!(env!("CARGO_PKG_NAME"), "\n", help!())),
-            "-l" => match args.next() {
-                Some(listen) => Ok(listen),
-                None => Err("invalid listen argument, required a value."),
-            },
-            "-V" => Err(env!("CARGO_PKG_VERSION")),
-            _ => Err(r#"invalid options, use "-h" to show help"#),
-        },
-        None => Ok("127.0.0.1:1080".to_owned()),
-    };
-    let listen = match listen {
-        Ok(listen) => listen,
+    #[cfg(feature = "bench")]
+    if std::env::args().nth(1).as_deref() == Some("bench") {
+        if let Err(e) = bench::run(std::env::args().skip(2)) {
+            println!("bench error: {}", e)
+        }
+        return;
+    }
+    let options = match parse_args() {
+        Ok(options) => options,
         Err(e) => return println!("{}", e),
     };
-    if let Err(e) = run(&listen) {
+    if let Err(e) = run(&options) {
         println!("startup error: {}", e)
     }
 }
@@ -0,0 +1,258 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use futures_lite::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// the longest v1 header, including CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// reads a PROXY protocol (v1 or v2) header without reading beyond it, so the
+/// rest of the stream is left for the socks5 handshake.
+///
+/// returns the original client address, or `None` if the header does not
+/// carry one (`UNKNOWN` in v1, `LOCAL` or an unspecified family in v2).
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut prefix = [0; 5];
+    stream.read_exact(&mut prefix).await?;
+    if &prefix == b"PROXY" {
+        read_v1(stream).await
+    } else if prefix == V2_SIGNATURE[..5] {
+        read_v2(stream).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = b"PROXY".to_vec();
+    // the v1 header has no length, read it byte by byte up to CRLF
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LENGTH {
+            return Err(invalid("PROXY protocol v1 header is too long"));
+        }
+        let mut byte = [0];
+        stream.read_exact(&mut byte).await?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("invalid PROXY protocol v1 header"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), src, _, port, _] => {
+            let ip: IpAddr = src
+                .parse()
+                .map_err(|_| invalid("invalid PROXY protocol v1 source address"))?;
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                return Err(invalid("PROXY protocol v1 address family mismatch"));
+            }
+            let port = port
+                .parse()
+                .map_err(|_| invalid("invalid PROXY protocol v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("invalid PROXY protocol v1 header")),
+    }
+}
+
+async fn read_v2<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0; 11];
+    stream.read_exact(&mut header).await?;
+    if header[..7] != V2_SIGNATURE[5..] {
+        return Err(invalid("missing PROXY protocol header"));
+    }
+    let (version_command, family) = (header[7], header[8]);
+    let len = u16::from_be_bytes([header[9], header[10]]) as usize;
+    // addresses, followed by TLVs which are skipped
+    let mut addresses = vec![0; len];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL, e.g. health checks of the load balancer itself
+        0 => return Ok(None),
+        1 => (),
+        _ => return Err(invalid("unsupported PROXY protocol command")),
+    }
+    let port = |offset: usize| u16::from_be_bytes([addresses[offset], addresses[offset + 1]]);
+    // the high nibble is the address family, the low one the transport protocol
+    match family >> 4 {
+        1 if len >= 12 => {
+            let mut ip = [0; 4];
+            ip.copy_from_slice(&addresses[..4]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        2 if len >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        1 | 2 => Err(invalid("truncated PROXY protocol v2 addresses")),
+        // AF_UNSPEC or AF_UNIX
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{ErrorKind, Result},
+        net::{Ipv6Addr, SocketAddr},
+    };
+
+    use futures_lite::{future::block_on, io::Cursor, AsyncReadExt};
+
+    use super::{read_header, V2_SIGNATURE};
+
+    /// parses the header at the start of `data`, and returns what is left
+    /// after it.
+    fn parse(data: &[u8]) -> Result<(Option<SocketAddr>, Vec<u8>)> {
+        block_on(async {
+            let mut stream = Cursor::new(data);
+            let src = read_header(&mut stream).await?;
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).await?;
+            Ok((src, rest))
+        })
+    }
+
+    fn addr(s: &str) -> Option<SocketAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let data = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n\x05\x01\x00";
+        let (src, rest) = parse(data).unwrap();
+        assert_eq!(src, addr("192.0.2.1:56324"));
+        assert_eq!(rest, [5, 1, 0]);
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let data = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n\x05";
+        let (src, rest) = parse(data).unwrap();
+        assert_eq!(src, addr("[2001:db8::1]:56324"));
+        assert_eq!(rest, [5]);
+    }
+
+    #[test]
+    fn v1_unknown() {
+        let (src, rest) = parse(b"PROXY UNKNOWN\r\n\x05").unwrap();
+        assert_eq!(src, None);
+        assert_eq!(rest, [5]);
+        // the addresses of UNKNOWN are ignored
+        let data = b"PROXY UNKNOWN ffff:f::1 ffff:f::2 65535 65535\r\n";
+        assert_eq!(parse(data).unwrap().0, None);
+    }
+
+    #[test]
+    fn v1_too_long() {
+        // 107 bytes is the longest valid header, this one has no CRLF within
+        let mut data = b"PROXY UNKNOWN ".to_vec();
+        data.resize(120, b'a');
+        data.extend_from_slice(b"\r\n");
+        let err = parse(&data).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn v1_family_mismatch() {
+        let data = b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 443\r\n";
+        assert_eq!(parse(data).unwrap_err().kind(), ErrorKind::InvalidData);
+        let data = b"PROXY TCP6 192.0.2.1 198.51.100.1 56324 443\r\n";
+        assert_eq!(parse(data).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn v2_ipv4_with_tlvs() {
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        // PP2_TYPE_AUTHORITY "example.com", then PP2_TYPE_NOOP
+        addresses.extend_from_slice(b"\x02\x00\x0bexample.com\x04\x00\x00");
+        let mut data = v2(1, 0x11, &addresses);
+        data.extend_from_slice(&[5, 1, 0]);
+        let (src, rest) = parse(&data).unwrap();
+        assert_eq!(src, addr("192.0.2.1:56324"));
+        assert_eq!(rest, [5, 1, 0]);
+    }
+
+    #[test]
+    fn v2_ipv6_with_tlvs() {
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut addresses = src.octets().to_vec();
+        addresses.extend_from_slice(&dst.octets());
+        addresses.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        addresses.extend_from_slice(b"\x04\x00\x03abc");
+        let mut data = v2(1, 0x21, &addresses);
+        data.extend_from_slice(&[5]);
+        let (src, rest) = parse(&data).unwrap();
+        assert_eq!(src, addr("[2001:db8::1]:56324"));
+        assert_eq!(rest, [5]);
+    }
+
+    #[test]
+    fn v2_local() {
+        // a LOCAL header may carry addresses, they are ignored
+        let mut data = v2(0, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0, 1, 0, 2]);
+        data.extend_from_slice(&[5]);
+        let (src, rest) = parse(&data).unwrap();
+        assert_eq!(src, None);
+        assert_eq!(rest, [5]);
+        assert_eq!(parse(&v2(0, 0, &[])).unwrap().0, None);
+    }
+
+    #[test]
+    fn v2_truncated_addresses() {
+        // shorter than the addresses of the family
+        let data = v2(1, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1]);
+        assert_eq!(parse(&data).unwrap_err().kind(), ErrorKind::InvalidData);
+        let data = v2(1, 0x21, &[0; 32]);
+        assert_eq!(parse(&data).unwrap_err().kind(), ErrorKind::InvalidData);
+        // shorter than the length says
+        let mut data = v2(1, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0, 1, 0, 2]);
+        data.truncate(data.len() - 4);
+        let err = parse(&data).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn missing_signature() {
+        // a socks5 handshake without the header
+        let err = parse(&[5, 1, 0, 5, 1, 0, 1]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = parse(b"PROXX TCP4 192.0.2.1 198.51.100.1 1 2\r\n").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        // the first bytes of the v2 signature only
+        let mut data = V2_SIGNATURE[..5].to_vec();
+        data.extend_from_slice(&[0; 11]);
+        let err = parse(&data).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,195 @@
+use std::{collections::HashMap, net::IpAddr, time::Instant};
+
+// tracked clients before idle buckets are pruned, and the least recently seen
+// evicted if that is not enough
+const MAX_BUCKETS: usize = 65536;
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// a token bucket of new connections per client address, IPv6 addresses are
+/// aggregated by prefix, so a client can not evade it by rotating addresses
+/// in its own network.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    ipv6_prefix: u8,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64, ipv6_prefix: u8) -> RateLimiter {
+        RateLimiter {
+            rate,
+            burst,
+            ipv6_prefix,
+            buckets: HashMap::new(),
+        }
+    }
+
+    fn key(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(_) => ip,
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => IpAddr::V4(ip),
+                None => {
+                    let mask = u128::MAX
+                        .checked_shl(128 - self.ipv6_prefix as u32)
+                        .unwrap_or(0);
+                    IpAddr::V6((u128::from(ip) & mask).into())
+                }
+            },
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+
+    /// takes a token for a new connection from `ip`, returns `false` if it
+    /// exceeds the rate.
+    pub fn check(&mut self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.buckets.len() >= MAX_BUCKETS {
+            self.prune(now);
+        }
+        let key = self.key(ip);
+        let tokens = match self.buckets.get(&key) {
+            Some(bucket) => self.refill(bucket, now),
+            None => self.burst,
+        };
+        let allowed = tokens >= 1.0;
+        let tokens = if allowed { tokens - 1.0 } else { tokens };
+        self.buckets.insert(key, Bucket { tokens, last: now });
+        allowed
+    }
+
+    // a full bucket is the same as an absent one
+    fn prune(&mut self, now: Instant) {
+        let buckets = std::mem::take(&mut self.buckets);
+        self.buckets = buckets
+            .into_iter()
+            .filter(|(_, bucket)| self.refill(bucket, now) < self.burst)
+            .collect();
+        // still flooded by distinct clients, forget the least recently seen quarter
+        // rather than grow unbounded, the recent ones, likely the flooding
+        // client among them, stay limited
+        if self.buckets.len() >= MAX_BUCKETS {
+            let mut seen: Vec<Instant> = self.buckets.values().map(|bucket| bucket.last).collect();
+            let (_, &mut cutoff, _) = seen.select_nth_unstable(MAX_BUCKETS / 4);
+            self.buckets.retain(|_, bucket| bucket.last > cutoff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        time::{Duration, Instant},
+    };
+
+    use super::{RateLimiter, MAX_BUCKETS};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn burst_is_exhausted() {
+        let mut limiter = RateLimiter::new(1.0, 3.0, 64);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at(ip("192.0.2.1"), now));
+        }
+        assert!(!limiter.check_at(ip("192.0.2.1"), now));
+        assert!(!limiter.check_at(ip("192.0.2.1"), now));
+        // other clients are not affected
+        assert!(limiter.check_at(ip("192.0.2.2"), now));
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let mut limiter = RateLimiter::new(2.0, 2.0, 64);
+        let now = Instant::now();
+        assert!(limiter.check_at(ip("192.0.2.1"), now));
+        assert!(limiter.check_at(ip("192.0.2.1"), now));
+        assert!(!limiter.check_at(ip("192.0.2.1"), now));
+        // 2 per second, one token after half a second
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.check_at(ip("192.0.2.1"), later));
+        assert!(!limiter.check_at(ip("192.0.2.1"), later));
+        // refilled up to the burst only
+        let much_later = later + Duration::from_secs(60);
+        assert!(limiter.check_at(ip("192.0.2.1"), much_later));
+        assert!(limiter.check_at(ip("192.0.2.1"), much_later));
+        assert!(!limiter.check_at(ip("192.0.2.1"), much_later));
+    }
+
+    #[test]
+    fn ipv6_is_aggregated_by_prefix() {
+        let mut limiter = RateLimiter::new(1.0, 1.0, 64);
+        let now = Instant::now();
+        assert!(limiter.check_at(ip("2001:db8:0:1::1"), now));
+        // same /64
+        assert!(!limiter.check_at(ip("2001:db8:0:1::2"), now));
+        assert!(!limiter.check_at(ip("2001:db8:0:1:ffff:ffff:ffff:ffff"), now));
+        // another /64
+        assert!(limiter.check_at(ip("2001:db8:0:2::1"), now));
+    }
+
+    #[test]
+    fn ipv4_mapped_is_ipv4() {
+        let limiter = RateLimiter::new(1.0, 1.0, 64);
+        let mapped = IpAddr::V6(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped());
+        assert_eq!(limiter.key(mapped), ip("192.0.2.1"));
+
+        let mut limiter = RateLimiter::new(1.0, 1.0, 64);
+        let now = Instant::now();
+        assert!(limiter.check_at(mapped, now));
+        assert!(!limiter.check_at(ip("192.0.2.1"), now));
+        // mapped addresses are not aggregated by the IPv6 prefix
+        assert!(limiter.check_at(ip("::ffff:192.0.2.2"), now));
+    }
+
+    #[test]
+    fn prefix_edges() {
+        let limiter = RateLimiter::new(1.0, 1.0, 0);
+        assert_eq!(
+            limiter.key(ip("2001:db8::1")),
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        );
+        assert_eq!(limiter.key(ip("192.0.2.1")), ip("192.0.2.1"));
+
+        let mut limiter = RateLimiter::new(1.0, 1.0, 128);
+        assert_eq!(limiter.key(ip("2001:db8::1")), ip("2001:db8::1"));
+        let now = Instant::now();
+        assert!(limiter.check_at(ip("2001:db8::1"), now));
+        assert!(limiter.check_at(ip("2001:db8::2"), now));
+        assert!(!limiter.check_at(ip("2001:db8::1"), now));
+    }
+
+    #[test]
+    fn full_table_keeps_recent_clients() {
+        // slow enough that no bucket refills during the test
+        let mut limiter = RateLimiter::new(0.001, 1.0, 128);
+        let start = Instant::now();
+        let client = |i: usize| IpAddr::V6(Ipv6Addr::from(i as u128 + 1));
+        for i in 0..MAX_BUCKETS {
+            let now = start + Duration::from_millis(i as u64);
+            assert!(limiter.check_at(client(i), now));
+        }
+        // the table is full of active buckets, the most recent client stays limited
+        let now = start + Duration::from_millis(MAX_BUCKETS as u64);
+        assert!(!limiter.check_at(client(MAX_BUCKETS - 1), now));
+        assert!(limiter.buckets.len() < MAX_BUCKETS);
+        // while the least recently seen are forgotten
+        assert!(limiter.check_at(client(0), now));
+    }
+}
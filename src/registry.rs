@@ -0,0 +1,117 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Mutex, PoisonError},
+};
+#[cfg(unix)]
+use std::{io::Result, sync::Arc, thread, time::Instant};
+
+#[cfg(unix)]
+use signal_hook::{consts::SIGUSR1, iterator::Signals};
+
+struct Entry {
+    client: SocketAddr,
+    destination: Option<SocketAddr>,
+    #[cfg(unix)]
+    since: Instant,
+}
+
+/// the active connections, logged on SIGUSR1 (unix only).
+#[derive(Default)]
+pub struct Registry {
+    connections: Mutex<HashMap<u64, Entry>>,
+}
+
+/// removes its connection from the registry when dropped, however the
+/// connection ends.
+pub struct Registration<'a> {
+    registry: &'a Registry,
+    id: u64,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.registry
+            .connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.id);
+    }
+}
+
+impl Registry {
+    pub fn register(&self, id: u64, client: SocketAddr) -> Registration<'_> {
+        let entry = Entry {
+            client,
+            destination: None,
+            #[cfg(unix)]
+            since: Instant::now(),
+        };
+        self.connections.lock().unwrap().insert(id, entry);
+        Registration { registry: self, id }
+    }
+
+    /// replaces the client address, once it is taken from a PROXY protocol
+    /// header.
+    pub fn set_client(&self, id: u64, client: SocketAddr) {
+        if let Some(entry) = self.connections.lock().unwrap().get_mut(&id) {
+            entry.client = client;
+        }
+    }
+
+    #[cfg(all(feature = "transparent", target_os = "linux"))]
+    pub fn set_destination(&self, id: u64, destination: SocketAddr) {
+        if let Some(entry) = self.connections.lock().unwrap().get_mut(&id) {
+            entry.destination = Some(destination);
+        }
+    }
+
+    /// the client address of connection `id`, and its destination if known.
+    pub fn addresses(&self, id: u64) -> Option<(SocketAddr, Option<SocketAddr>)> {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|entry| (entry.client, entry.destination))
+    }
+
+    #[cfg(unix)]
+    fn dump(&self) {
+        let now = Instant::now();
+        // copied out, so the lock is not held while logging
+        let mut connections: Vec<_> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, entry)| {
+                let age = now.duration_since(entry.since);
+                (*id, entry.client, entry.destination, age)
+            })
+            .collect();
+        connections.sort_by_key(|(id, ..)| *id);
+        println!("active connections: {}", connections.len());
+        for (id, client, destination, age) in connections {
+            let destination = destination.map_or_else(|| "-".to_owned(), |dst| dst.to_string());
+            println!(
+                "connection: id={} client={} destination={} age={}s",
+                id,
+                client,
+                destination,
+                age.as_secs()
+            );
+        }
+    }
+}
+
+/// logs the active connections each time SIGUSR1 is received.
+#[cfg(unix)]
+pub fn dump_on_signal(registry: Arc<Registry>) -> Result<()> {
+    let mut signals = Signals::new([SIGUSR1])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            registry.dump();
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,331 @@
+use std::{
+    fmt,
+    io::{Error, Result},
+    mem,
+    net::{
+        Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener,
+        TcpStream,
+    },
+    os::unix::io::{AsRawFd, RawFd},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use async_io::{Async, Timer};
+use futures_lite::{future, pin, AsyncReadExt, AsyncWriteExt};
+
+// from linux/netfilter_ipv4.h and linux/netfilter_ipv6/ip6_tables.h
+const SO_ORIGINAL_DST: libc::c_int = 80;
+const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+// from linux/in6.h
+const IPV6_TRANSPARENT: libc::c_int = 75;
+
+// how long a relay waits for the other side once one side has finished
+const HALF_CLOSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// # Safety
+///
+/// `T` must be the C type the option `level`/`name` yields, a plain struct
+/// valid when zeroed or filled in by the kernel.
+unsafe fn getsockopt<T>(fd: RawFd, level: libc::c_int, name: libc::c_int) -> Result<T> {
+    let mut value: T = mem::zeroed();
+    let mut len = mem::size_of::<T>() as libc::socklen_t;
+    let ret = libc::getsockopt(
+        fd,
+        level,
+        name,
+        &mut value as *mut T as *mut libc::c_void,
+        &mut len,
+    );
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(value)
+}
+
+/// allows `listener` to accept connections intercepted by a `TPROXY` rule,
+/// requires `CAP_NET_ADMIN`.
+pub fn set_transparent(listener: &TcpListener) -> Result<()> {
+    let (level, name) = match listener.local_addr()? {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, IPV6_TRANSPARENT),
+    };
+    let enable: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            listener.as_raw_fd(),
+            level,
+            name,
+            &enable as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// the destination a client originally connected to, before its connection
+/// was redirected (`REDIRECT`, recorded by conntrack) or intercepted
+/// (`TPROXY`, kept as the local address of the accepted socket).
+pub fn original_destination(stream: &TcpStream) -> Result<SocketAddr> {
+    let local = match stream.local_addr()? {
+        // an IPv4 connection accepted by a dual-stack listener, conntrack has
+        // it as IPv4, and `SOL_IP` options apply to such a socket too
+        SocketAddr::V6(addr) => match addr.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), addr.port()),
+            None => SocketAddr::V6(addr),
+        },
+        local => local,
+    };
+    let fd = stream.as_raw_fd();
+    let dst = match local {
+        SocketAddr::V4(_) => {
+            // SAFETY: SO_ORIGINAL_DST yields a `sockaddr_in`
+            let addr =
+                unsafe { getsockopt::<libc::sockaddr_in>(fd, libc::SOL_IP, SO_ORIGINAL_DST) };
+            addr.map(|addr| {
+                SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                    u16::from_be(addr.sin_port),
+                ))
+            })
+        }
+        SocketAddr::V6(_) => {
+            // SAFETY: IP6T_SO_ORIGINAL_DST yields a `sockaddr_in6`
+            let addr = unsafe {
+                getsockopt::<libc::sockaddr_in6>(fd, libc::SOL_IPV6, IP6T_SO_ORIGINAL_DST)
+            };
+            addr.map(|addr| {
+                SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                ))
+            })
+        }
+    };
+    match dst {
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(local),
+        dst => dst,
+    }
+}
+
+/// why a relay ended.
+#[derive(Clone, Copy)]
+pub enum CloseReason {
+    ClientEof,
+    ServerEof,
+    /// one side finished, the other did not in time
+    HalfCloseTimeout,
+    /// no data in either direction for the idle timeout
+    HalfOpenTimeout,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CloseReason::ClientEof => "client-eof",
+            CloseReason::ServerEof => "server-eof",
+            CloseReason::HalfCloseTimeout => "half-close-timeout",
+            CloseReason::HalfOpenTimeout => "half-open-timeout",
+        })
+    }
+}
+
+/// when data last moved in either direction of a relay.
+struct Activity {
+    start: Instant,
+    // milliseconds since `start`
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Activity {
+        Activity {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last.store(now, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+}
+
+/// copies until EOF, then passes the EOF on with an orderly shutdown, so the
+/// final bytes reach the peer before the sockets are dropped.
+async fn half_relay(
+    mut from: &Async<TcpStream>,
+    mut to: &Async<TcpStream>,
+    activity: &Activity,
+) -> Result<()> {
+    let mut buf = vec![0; 8192];
+    loop {
+        let n = from.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        activity.touch();
+        to.write_all(&buf[..n]).await?;
+    }
+    to.flush().await?;
+    to.get_ref().shutdown(Shutdown::Write)
+}
+
+async fn idle_timeout(activity: &Activity, timeout: Duration) -> Result<CloseReason> {
+    loop {
+        let remaining = timeout.saturating_sub(activity.idle());
+        if remaining.is_zero() {
+            return Ok(CloseReason::HalfOpenTimeout);
+        }
+        Timer::after(remaining).await;
+    }
+}
+
+/// connects to `dst` and relays until both sides finish, the reason is the
+/// side which finished first.
+///
+/// with `idle_timeout`, a relay with no data in either direction for that
+/// long is closed, e.g. as a peer has silently disappeared.
+pub async fn relay(
+    client: Async<TcpStream>,
+    dst: SocketAddr,
+    idle_timeout: Option<Duration>,
+) -> Result<CloseReason> {
+    let server = Async::<TcpStream>::connect(dst).await?;
+    relay_streams(&client, &server, HALF_CLOSE_TIMEOUT, idle_timeout).await
+}
+
+// a side which never finishes would otherwise keep the relay open, once the
+// other side has finished it is given `half_close_timeout`
+async fn relay_streams(
+    client: &Async<TcpStream>,
+    server: &Async<TcpStream>,
+    half_close_timeout: Duration,
+    idle: Option<Duration>,
+) -> Result<CloseReason> {
+    let activity = Activity::new();
+    let relayed = async {
+        let client_eof = async {
+            half_relay(client, server, &activity).await?;
+            Ok::<_, Error>(CloseReason::ClientEof)
+        };
+        let server_eof = async {
+            half_relay(server, client, &activity).await?;
+            Ok::<_, Error>(CloseReason::ServerEof)
+        };
+        pin!(client_eof, server_eof);
+        let first = future::or(&mut client_eof, &mut server_eof).await?;
+        let other = async {
+            match first {
+                CloseReason::ClientEof => server_eof.await?,
+                _ => client_eof.await?,
+            };
+            Ok(first)
+        };
+        let timeout = async {
+            Timer::after(half_close_timeout).await;
+            Ok(CloseReason::HalfCloseTimeout)
+        };
+        future::or(other, timeout).await
+    };
+    match idle {
+        Some(timeout) => future::or(relayed, idle_timeout(&activity, timeout)).await,
+        None => relayed.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Error,
+        net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+        time::Duration,
+    };
+
+    use async_io::{block_on, Async};
+    use futures_lite::{future, AsyncReadExt, AsyncWriteExt};
+
+    use super::{relay, relay_streams, CloseReason, HALF_CLOSE_TIMEOUT};
+
+    /// a connected pair of sockets on loopback.
+    fn pair() -> (Async<TcpStream>, Async<TcpStream>) {
+        let listener = Async::<TcpListener>::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.get_ref().local_addr().unwrap();
+        let accepted = async { listener.accept().await.map(|(stream, _)| stream) };
+        block_on(future::try_zip(Async::<TcpStream>::connect(addr), accepted)).unwrap()
+    }
+
+    #[test]
+    fn final_bytes_arrive_after_close() {
+        let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let listener = Async::<TcpListener>::bind(localhost).unwrap();
+        let destination = Async::<TcpListener>::bind(localhost).unwrap();
+        let listener_addr = listener.get_ref().local_addr().unwrap();
+        let destination_addr = destination.get_ref().local_addr().unwrap();
+        // large enough to be still in flight when the sender closes
+        let request: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+        let response: Vec<u8> = request.iter().rev().copied().collect();
+
+        let relayed = async {
+            let (stream, _) = listener.accept().await?;
+            relay(stream, destination_addr, None).await
+        };
+        let client = async {
+            let mut stream = Async::<TcpStream>::connect(listener_addr).await?;
+            stream.write_all(&request).await?;
+            stream.get_ref().shutdown(Shutdown::Write)?;
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await?;
+            Ok::<_, Error>(received)
+        };
+        let server = async {
+            let (mut stream, _) = destination.accept().await?;
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await?;
+            // closed right after the last write
+            stream.write_all(&response).await?;
+            Ok::<_, Error>(received)
+        };
+        let (reason, received) = block_on(future::zip(relayed, future::try_zip(client, server)));
+        let (client_received, server_received) = received.unwrap();
+        assert!(server_received == request, "request truncated");
+        assert!(client_received == response, "response truncated");
+        assert!(matches!(reason.unwrap(), CloseReason::ClientEof));
+    }
+
+    #[test]
+    fn half_close_times_out() {
+        // the relay's sockets, and the client and server at their other ends
+        let (client, client_end) = pair();
+        let (server, _server_end) = pair();
+        client_end.get_ref().shutdown(Shutdown::Write).unwrap();
+        // the server neither writes nor closes
+        let reason = block_on(relay_streams(
+            &client,
+            &server,
+            Duration::from_millis(100),
+            None,
+        ));
+        assert!(matches!(reason.unwrap(), CloseReason::HalfCloseTimeout));
+    }
+
+    #[test]
+    fn idle_times_out() {
+        let (client, _client_end) = pair();
+        let (server, _server_end) = pair();
+        // neither side writes nor closes
+        let timeout = Some(Duration::from_millis(100));
+        let reason = block_on(relay_streams(&client, &server, HALF_CLOSE_TIMEOUT, timeout));
+        assert!(matches!(reason.unwrap(), CloseReason::HalfOpenTimeout));
+    }
+}